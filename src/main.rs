@@ -1,9 +1,63 @@
 // constants and structs from usr/src/uts/common/sys/swap.h
 
+use bitflags::bitflags;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use tracing::{debug, warn};
+
+/// Errors returned by the public `swapctl_*` wrappers. Unlike the raw
+/// `swapctl(2)` binding, these are returned rather than panicking, so this
+/// crate can run inside a long-lived daemon without aborting on a bad path
+/// or misaligned offset.
+#[derive(Debug)]
+pub enum SwapError {
+    /// `value` must be a multiple of the 512-byte block size.
+    NotBlockAligned { value: u64 },
+    /// A device path contained a NUL byte and can't be passed to
+    /// `swapctl(2)`.
+    NulInPath,
+    /// The underlying `swapctl(2)` call failed.
+    Syscall(std::io::Error),
+    /// `SC_LIST` reported more devices than we're willing to allocate for.
+    TooManyDevices { found: usize },
+}
+
+impl std::fmt::Display for SwapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SwapError::NotBlockAligned { value } => {
+                write!(f, "{value} is not a multiple of the 512-byte block size")
+            }
+            SwapError::NulInPath => write!(f, "path contains a NUL byte"),
+            SwapError::Syscall(e) => write!(f, "swapctl(2) failed: {e}"),
+            SwapError::TooManyDevices { found } => write!(
+                f,
+                "found {found} swap devices, more than this process will allocate for"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SwapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SwapError::Syscall(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SwapError {
+    fn from(e: std::io::Error) -> Self {
+        SwapError::Syscall(e)
+    }
+}
+
 // swapctl(2) commands
 const SC_ADD: i32 = 0x1;
 const SC_LIST: i32 = 0x2;
-const _SC_REMOVE: i32 = 0x3;
+const SC_REMOVE: i32 = 0x3;
 const SC_GETNSWP: i32 = 0x4;
 const SC_AINFO: i32 = 0x5;
 
@@ -21,12 +75,19 @@ pub struct swapres {
     sr_length: libc::off_t,
 }
 
-// SC_LIST arg
+// SC_LIST arg.
+//
+// `swt_ent` is a C flexible array member: swapctl(2) expects `swt_n`
+// `swapent`s laid out directly after this header in the same allocation,
+// not embedded inline in the struct. We model that with a zero-length
+// array, which under `repr(C)` pulls `swapent`'s alignment into the
+// struct's layout without claiming any of its own size, so `swt_ent`
+// entries start at the correctly-aligned offset right after `swt_n`.
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct swaptbl {
     swt_n: i32,
-    swt_ent: [swapent; N_SWAPENTS],
+    swt_ent: [swapent; 0],
 }
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -51,21 +112,22 @@ impl Default for swapent {
     }
 }
 
-// The argument for SC_LIST (swaptbl) requires an embedded array in the struct,
-// with swt_n entries, each of which requires a pointer to store the path to the
-// device.
-//
-// Ideally, we would want to query the number of swap devices on the system via
-// SC_GETNSWP, allocate enough memory for the number of devices, then list the
-// swap devices. Creating a generically large array embedded in a struct that
-// can be passed to C is a bit of a challenge in safe Rust. So instead, we just
-// pick a reasonable max number of devices to list.
-//
-// We pick a max of 3 devices, somewhat arbitrarily, but log the number of
-// swap devices we see regardless. We only ever expect to see 0 or 1 swap
-// device(s); if there are more, that is a bug. In this case we log a warning,
-// and eventually, we should send an ereport.
-const N_SWAPENTS: usize = 3;
+// Extra slots to request beyond the last known device count, to tolerate a
+// device being added between the SC_GETNSWP and SC_LIST calls below.
+const SWAPENT_SLACK: usize = 1;
+
+/// Allocate a zeroed, correctly-aligned buffer large enough to hold a
+/// `swaptbl` header followed by `n` `swapent` entries. The returned `Vec`
+/// backs the allocation; it must outlive any pointer derived from it.
+fn alloc_swaptbl(n: usize) -> Vec<u64> {
+    let bytes = mem::size_of::<swaptbl>() + n * mem::size_of::<swapent>();
+    vec![0u64; bytes.div_ceil(mem::size_of::<u64>())]
+}
+
+unsafe fn swaptbl_entries_mut<'a>(tbl: *mut swaptbl, n: usize) -> &'a mut [swapent] {
+    let base = (tbl as *mut u8).add(mem::size_of::<swaptbl>()) as *mut swapent;
+    std::slice::from_raw_parts_mut(base, n)
+}
 
 unsafe fn swapctl_cmd<T>(cmd: i32, data: Option<*mut T>) -> std::io::Result<u32> {
     assert!(cmd >= 0 && cmd <= SC_AINFO, "invalid swapctl cmd: {cmd}");
@@ -77,92 +139,423 @@ unsafe fn swapctl_cmd<T>(cmd: i32, data: Option<*mut T>) -> std::io::Result<u32>
 
     let res = swapctl(cmd, ptr);
     if res == -1 {
-        // TODO: log message
-        // TODO: custom error
-        return Err(std::io::Error::last_os_error());
+        let err = std::io::Error::last_os_error();
+        warn!(cmd, %err, "swapctl(2) call failed");
+        return Err(err);
     }
 
     Ok(res as u32)
 }
 
-pub fn swapctl_get_num_devices() -> std::io::Result<u32> {
-    unsafe { swapctl_cmd::<i32>(SC_GETNSWP, None) }
+// Upper bound on how many swap devices we'll allocate room for. We only
+// ever expect to see 0 or 1 device; if SC_LIST keeps reporting more than
+// this across retries, something is wrong and we give up rather than
+// growing the allocation without limit.
+const MAX_SWAP_DEVICES: usize = 4096;
+
+pub(crate) fn swapctl_get_num_devices() -> Result<u32, SwapError> {
+    let n = unsafe { swapctl_cmd::<i32>(SC_GETNSWP, None) }?;
+    Ok(n)
 }
 
-// TODO: probably want to return a real Rust struct here
-pub fn swapctl_list() -> std::io::Result<(usize, swaptbl)> {
-    // statically allocate the array of swapents for SC_LIST
-    //
-    // see comment on `N_SWAPENTS` for details
+// The `Vec<[c_char; PATH_MAX]>` of path buffers is returned alongside the
+// entries because each entry's `ste_path` points into one of them; the
+// caller must keep both alive for as long as it reads the paths.
+type SwapListResult = Result<
+    (
+        usize,
+        Vec<swapent>,
+        Vec<[libc::c_char; libc::PATH_MAX as usize]>,
+    ),
+    SwapError,
+>;
+
+// This is a raw, unsafe-to-use binding; callers should prefer
+// `list_swap_devices`.
+pub(crate) fn swapctl_list() -> SwapListResult {
     const MAXPATHLEN: usize = libc::PATH_MAX as usize;
-    let p1 = [0i8; MAXPATHLEN];
-    let p2 = [0i8; MAXPATHLEN];
-    let p3 = [0i8; MAXPATHLEN];
-
-    let entries: [swapent; N_SWAPENTS] = [
-        swapent {
-            ste_path: &p1 as *const libc::c_char,
-            ..Default::default()
-        },
-        swapent {
-            ste_path: &p2 as *const libc::c_char,
-            ..Default::default()
-        },
-        swapent {
-            ste_path: &p3 as *const libc::c_char,
-            ..Default::default()
-        },
-    ];
-
-    let mut list_req = swaptbl {
-        swt_n: N_SWAPENTS as i32,
-        swt_ent: entries,
-    };
 
-    let n_devices = unsafe { swapctl_cmd(SC_LIST, Some(&mut list_req))? };
+    // Learn the device count up front instead of guessing at a fixed-size
+    // array, but the count can still grow between this call and SC_LIST, so
+    // the loop below retries with a bigger allocation if that happens (this
+    // mirrors the rnswap-vs-nswap reconciliation NetBSD's SWAP_STATS does).
+    let mut n = swapctl_get_num_devices()? as usize + SWAPENT_SLACK;
+
+    loop {
+        if n > MAX_SWAP_DEVICES {
+            return Err(SwapError::TooManyDevices {
+                found: n - SWAPENT_SLACK,
+            });
+        }
+
+        let mut paths: Vec<[libc::c_char; MAXPATHLEN]> = vec![[0; MAXPATHLEN]; n];
+        let mut buf = alloc_swaptbl(n);
+        let tbl = buf.as_mut_ptr() as *mut swaptbl;
+
+        unsafe {
+            (*tbl).swt_n = n as i32;
+            for (ent, path) in swaptbl_entries_mut(tbl, n).iter_mut().zip(paths.iter_mut()) {
+                *ent = swapent {
+                    ste_path: path.as_ptr(),
+                    ..Default::default()
+                };
+            }
+        }
+
+        let n_devices = unsafe { swapctl_cmd(SC_LIST, Some(tbl))? } as usize;
 
-    Ok((n_devices as usize, list_req))
+        if n_devices >= n {
+            // More devices showed up than we allocated room for; retry with
+            // a bigger request.
+            warn!(
+                requested = n,
+                found = n_devices,
+                "SC_LIST grew under us, retrying with a larger allocation"
+            );
+            n = n_devices + SWAPENT_SLACK;
+            continue;
+        }
+
+        let entries = unsafe { swaptbl_entries_mut(tbl, n) }.to_vec();
+        return Ok((n_devices, entries, paths));
+    }
 }
 
-// TODO: can start be negative (off_t is i64)
-pub fn swapctl_add(name: &str, start: u64, length: u64) -> std::io::Result<()> {
-    // start and length must be specified in 512-byte blocks
-    assert_eq!(start % 512, 0, "start not divisible by 512: {}", start);
-    assert_eq!(length % 512, 0, "length not divisible by 512: {}", length);
+bitflags! {
+    /// Per-device state reported in `ste_flags` by `SC_LIST`.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct SwapFlags: libc::c_long {
+        /// Device is in the process of being deleted (illumos ST_INDEL).
+        const IN_DELETION = 0x01;
+        /// `SC_REMOVE` has been issued for this device but the deletion
+        /// hasn't completed yet (illumos ST_DOINGDEL).
+        const DOING_DELETE = 0x02;
+    }
+}
+
+/// An owned, safe view of one swap device, as reported by `SC_LIST`.
+#[derive(Debug, Clone)]
+pub struct SwapEntry {
+    pub path: PathBuf,
+    pub start: u64,
+    pub length: u64,
+    pub pages: u64,
+    pub free: u64,
+    pub flags: SwapFlags,
+}
+
+/// List the system's configured swap devices.
+pub fn list_swap_devices() -> Result<Vec<SwapEntry>, SwapError> {
+    let (n, entries, _paths) = swapctl_list()?;
+
+    Ok(entries
+        .into_iter()
+        .take(n)
+        .map(|e| {
+            // Safe: `_paths` (and the `ste_path` pointers into it) are kept
+            // alive for the rest of this function, and the path bytes are
+            // copied into an owned `PathBuf` below.
+            let raw = unsafe { std::ffi::CStr::from_ptr(e.ste_path) };
+            SwapEntry {
+                path: PathBuf::from(std::ffi::OsStr::from_bytes(raw.to_bytes())),
+                start: e.ste_start as u64,
+                length: e.ste_length as u64,
+                pages: e.ste_pages as u64,
+                free: e.ste_free as u64,
+                flags: SwapFlags::from_bits_truncate(e.ste_flags),
+            }
+        })
+        .collect())
+}
+
+// start and length must be specified in 512-byte blocks
+fn check_block_aligned(value: u64) -> Result<(), SwapError> {
+    if value % 512 != 0 {
+        return Err(SwapError::NotBlockAligned { value });
+    }
+    Ok(())
+}
 
-    // TODO: probably a real error here
-    let n = std::ffi::CString::new(name).unwrap();
+// Shared by swapctl_add/swapctl_remove, which build an identical `swapres`
+// (same block-alignment invariants on start/length) and only differ in
+// which `swapctl(2)` command they issue with it. The returned `CString`
+// must be kept alive for as long as the `swapres` is in use, since
+// `sr_name` points into it.
+fn build_swapres(
+    name: &str,
+    start: u64,
+    length: u64,
+) -> Result<(std::ffi::CString, swapres), SwapError> {
+    check_block_aligned(start)?;
+    check_block_aligned(length)?;
 
-    let mut add_req = swapres {
+    let n = std::ffi::CString::new(name).map_err(|_| SwapError::NulInPath)?;
+    let req = swapres {
         sr_name: n.as_ptr(),
         sr_start: start as libc::off_t,
         sr_length: length as libc::off_t,
     };
-    println!("add_req: {:?}", add_req);
+
+    Ok((n, req))
+}
+
+// TODO: can start be negative (off_t is i64)
+pub fn swapctl_add(name: &str, start: u64, length: u64) -> Result<(), SwapError> {
+    let (_n, mut add_req) = build_swapres(name, start, length)?;
+    debug!(?add_req, "issuing SC_ADD");
 
     let res = unsafe { swapctl_cmd(SC_ADD, Some(&mut add_req)) }?;
-    assert!(res == 0);
+    if res != 0 {
+        warn!(res, "SC_ADD returned an unexpected non-zero success code");
+    }
 
     Ok(())
 }
 
-fn main() {
-    let p = std::ptr::null_mut();
-    let r = unsafe { swapctl(SC_GETNSWP, p) };
-    println!("swapctl getnswp = {}", r);
-    let (n, lr) = swapctl_list().unwrap();
-    println!("swapctl listswap = {:?}\n", lr);
+// TODO: can start be negative (off_t is i64)
+pub fn swapctl_remove(name: &str, start: u64, length: u64) -> Result<(), SwapError> {
+    let (_n, mut remove_req) = build_swapres(name, start, length)?;
+    debug!(?remove_req, "issuing SC_REMOVE");
 
-    for i in 0..n {
-        let e = lr.swt_ent[i as usize];
-        let p = unsafe { std::ffi::CStr::from_ptr(e.ste_path) };
+    let res = unsafe { swapctl_cmd(SC_REMOVE, Some(&mut remove_req)) }?;
+    if res != 0 {
+        warn!(
+            res,
+            "SC_REMOVE returned an unexpected non-zero success code"
+        );
+    }
+
+    Ok(())
+}
+
+// SC_AINFO arg
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct anoninfo {
+    ani_max: libc::pgcnt_t,
+    ani_free: libc::pgcnt_t,
+    ani_resv: libc::pgcnt_t,
+}
+
+/// System-wide virtual swap accounting, as reported by `SC_AINFO`. Unlike
+/// `SC_LIST`, which reports per-device pages, this is the global
+/// reserved-vs-free picture that monitoring agents actually want.
+#[derive(Debug, Copy, Clone)]
+pub struct AnonInfo {
+    /// Total swap available to be reserved, in pages.
+    pub max: u64,
+    /// Swap currently unreserved, in pages.
+    pub free: u64,
+    /// Swap currently reserved (whether or not it's backed by physical
+    /// storage yet), in pages.
+    pub resv: u64,
+}
+
+pub fn swapctl_anon_info() -> Result<AnonInfo, SwapError> {
+    let mut info = anoninfo {
+        ani_max: 0,
+        ani_free: 0,
+        ani_resv: 0,
+    };
+
+    unsafe { swapctl_cmd(SC_AINFO, Some(&mut info)) }?;
+
+    Ok(AnonInfo {
+        max: info.ani_max as u64,
+        free: info.ani_free as u64,
+        resv: info.ani_resv as u64,
+    })
+}
+
+/// Convert a page count (e.g. a field of `AnonInfo` or `SwapEntry`) to bytes
+/// using the system page size (`sysconf(_SC_PAGESIZE)`).
+pub fn pages_to_bytes(pages: u64) -> Result<u64, SwapError> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size < 0 {
+        return Err(SwapError::Syscall(std::io::Error::last_os_error()));
+    }
+
+    Ok(pages * page_size as u64)
+}
+
+/// Unit to format the sizes in a `SwapSummary`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockSize {
+    /// 512-byte blocks, as used by `swapctl_add`/`swapctl_remove` and
+    /// illumos `swap -l`.
+    Blocks512,
+    Kilobytes,
+    Megabytes,
+}
+
+impl BlockSize {
+    fn bytes_per_unit(self) -> u64 {
+        match self {
+            BlockSize::Blocks512 => 512,
+            BlockSize::Kilobytes => 1024,
+            BlockSize::Megabytes => 1024 * 1024,
+        }
+    }
+}
+
+/// A human-readable swap report combining per-device listing (`SC_LIST`)
+/// with the system-wide reserved-vs-free accounting (`SC_AINFO`),
+/// reproducing the numbers illumos `swap -s`/`swap -l` and the classic BSD
+/// `swapinfo` report.
+#[derive(Debug, Clone)]
+pub struct SwapSummary {
+    pub blocksize: BlockSize,
+    pub devices: Vec<SwapEntry>,
+    pub total: u64,
+    pub used: u64,
+    pub available: u64,
+    pub percent_used: f64,
+}
+
+/// Compute `used` (`total - available`) and the percent-used figure for a
+/// `SwapSummary`, given `total`/`available` already converted to the
+/// caller's chosen `BlockSize` units.
+fn used_and_percent(total: u64, available: u64) -> (u64, f64) {
+    let used = total.saturating_sub(available);
+    let percent_used = if total == 0 {
+        0.0
+    } else {
+        used as f64 / total as f64 * 100.0
+    };
+    (used, percent_used)
+}
+
+pub fn swap_summary(blocksize: BlockSize) -> Result<SwapSummary, SwapError> {
+    let devices = list_swap_devices()?;
+    let anon = swapctl_anon_info()?;
+
+    let to_units = |pages: u64| -> Result<u64, SwapError> {
+        Ok(pages_to_bytes(pages)? / blocksize.bytes_per_unit())
+    };
+
+    let total = to_units(anon.max)?;
+    let available = to_units(anon.free)?;
+    let (used, percent_used) = used_and_percent(total, available);
+
+    Ok(SwapSummary {
+        blocksize,
+        devices,
+        total,
+        used,
+        available,
+        percent_used,
+    })
+}
+
+fn main() -> std::process::ExitCode {
+    tracing_subscriber::fmt::init();
+
+    let summary = match swap_summary(BlockSize::Megabytes) {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("swapctl: {e}");
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+
+    for d in &summary.devices {
         println!(
-            "swapfile {:?}: start {:?}, length {:?}, {:?} pages, {:?} free, 0x{:x} flags",
-            p, e.ste_start, e.ste_length, e.ste_pages, e.ste_free, e.ste_flags
+            "swapfile {:?}: start {}, length {}, {} pages, {} free, {:?}",
+            d.path, d.start, d.length, d.pages, d.free, d.flags
         );
     }
 
+    println!(
+        "total: {} MB, used: {} MB, available: {} MB, capacity: {:.0}%",
+        summary.total, summary.used, summary.available, summary.percent_used
+    );
+
     // TODO: how to get this path for the zvol?
     //let add = swapctl_add("/dev/zvol/dsk/rpool/testswap", 0, 0);
-    println!("add = {:?}", add);
+
+    std::process::ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_swaptbl_produces_a_buffer_long_enough_for_the_header_and_n_entries() {
+        for n in [0, 1, 2, 5] {
+            let buf = alloc_swaptbl(n);
+            let byte_len = buf.len() * mem::size_of::<u64>();
+            let needed = mem::size_of::<swaptbl>() + n * mem::size_of::<swapent>();
+            assert!(
+                byte_len >= needed,
+                "alloc_swaptbl({n}) produced {byte_len} bytes, need at least {needed}"
+            );
+        }
+    }
+
+    #[test]
+    fn swaptbl_entries_mut_lands_entries_on_an_aligned_offset() {
+        let mut buf = alloc_swaptbl(3);
+        let tbl = buf.as_mut_ptr() as *mut swaptbl;
+
+        let entries_addr = unsafe { swaptbl_entries_mut(tbl, 3) }.as_ptr() as usize;
+        let base_addr = tbl as usize;
+
+        assert_eq!(entries_addr - base_addr, mem::size_of::<swaptbl>());
+        assert_eq!(entries_addr % mem::align_of::<swapent>(), 0);
+    }
+
+    #[test]
+    fn block_aligned_accepts_multiples_of_512() {
+        assert!(check_block_aligned(0).is_ok());
+        assert!(check_block_aligned(512).is_ok());
+        assert!(check_block_aligned(512 * 7).is_ok());
+    }
+
+    #[test]
+    fn block_aligned_rejects_non_multiples_of_512() {
+        let err = check_block_aligned(511).unwrap_err();
+        assert!(matches!(err, SwapError::NotBlockAligned { value: 511 }));
+    }
+
+    #[test]
+    fn blocksize_bytes_per_unit() {
+        assert_eq!(BlockSize::Blocks512.bytes_per_unit(), 512);
+        assert_eq!(BlockSize::Kilobytes.bytes_per_unit(), 1024);
+        assert_eq!(BlockSize::Megabytes.bytes_per_unit(), 1024 * 1024);
+    }
+
+    #[test]
+    fn used_and_percent_typical() {
+        let (used, percent) = used_and_percent(1000, 400);
+        assert_eq!(used, 600);
+        assert_eq!(percent, 60.0);
+    }
+
+    #[test]
+    fn used_and_percent_available_exceeds_total() {
+        // If available is ever reported above total, used should saturate
+        // at 0 rather than underflow.
+        let (used, percent) = used_and_percent(100, 150);
+        assert_eq!(used, 0);
+        assert_eq!(percent, 0.0);
+    }
+
+    #[test]
+    fn used_and_percent_zero_total_does_not_divide_by_zero() {
+        let (used, percent) = used_and_percent(0, 0);
+        assert_eq!(used, 0);
+        assert_eq!(percent, 0.0);
+    }
+
+    #[test]
+    fn swap_flags_from_bits_truncate() {
+        assert_eq!(SwapFlags::from_bits_truncate(0x01), SwapFlags::IN_DELETION);
+        assert_eq!(SwapFlags::from_bits_truncate(0x02), SwapFlags::DOING_DELETE);
+        assert_eq!(
+            SwapFlags::from_bits_truncate(0x03),
+            SwapFlags::IN_DELETION | SwapFlags::DOING_DELETE
+        );
+        assert!(SwapFlags::from_bits_truncate(0x00).is_empty());
+    }
 }